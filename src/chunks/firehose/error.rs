@@ -0,0 +1,54 @@
+// Copyright 2022 Mandiant, Inc. All Rights Reserved
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not use this file except in compliance with the License. You may obtain a copy of the License at
+// http://www.apache.org/licenses/LICENSE-2.0
+// Unless required by applicable law or agreed to in writing, software distributed under the License
+// is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and limitations under the License.
+
+use nom::error::{ErrorKind, ParseError};
+
+/// Error surfaced by the firehose parsers. Used as nom's error parameter so a
+/// caller can tell "need more bytes" apart from "corrupt/unsupported entry" —
+/// a `nom::Err::Incomplete` sentinel conflated the two, which made triaging
+/// damaged or future-OS logarchives impossible.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FirehoseError {
+    /// A formatter-flag combination that the parser does not recognize. `raw`
+    /// is the full flag word and `masked` is the format sub-flags
+    /// (`FirehoseFlags::flags`), so downstream tooling can log the specific
+    /// decoding gap, skip the entry, and keep parsing the rest of the chunk.
+    UnknownFormatterFlags { raw: u16, masked: u16 },
+    /// The entry ended before a field the parser needed was read.
+    Truncated,
+    /// A nom combinator failed for another reason.
+    Nom(ErrorKind),
+}
+
+impl ParseError<&[u8]> for FirehoseError {
+    fn from_error_kind(_input: &[u8], kind: ErrorKind) -> FirehoseError {
+        match kind {
+            ErrorKind::Eof | ErrorKind::Complete => FirehoseError::Truncated,
+            other => FirehoseError::Nom(other),
+        }
+    }
+
+    fn append(_input: &[u8], _kind: ErrorKind, other: FirehoseError) -> FirehoseError {
+        other
+    }
+}
+
+impl core::fmt::Display for FirehoseError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            FirehoseError::UnknownFormatterFlags { raw, masked } => write!(
+                f,
+                "unknown firehose formatter flags: raw={raw:#X} masked={masked:#X}"
+            ),
+            FirehoseError::Truncated => write!(f, "firehose entry truncated"),
+            FirehoseError::Nom(kind) => write!(f, "firehose parse error: {kind:?}"),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for FirehoseError {}
@@ -5,16 +5,49 @@
 // is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
 // See the License for the specific language governing permissions and limitations under the License.
 
-use log::{debug, error};
+use alloc::format;
+use alloc::string::String;
 use nom::number::complete::{be_u128, le_u16};
-use nom::Needed;
 
+use crate::chunks::firehose::error::FirehoseError;
+
+/// Feature-gated trace hooks. The `std` feature is additive and on by default
+/// (`default = ["std"]`): it forwards to the `log` crate so existing DFIR
+/// tooling keeps the diagnostics the baseline emitted unconditionally.
+/// Building with `--no-default-features` for bare `core` + `alloc` targets
+/// collapses them to a no-op so the parser links into embedded/enclave
+/// environments without the std runtime.
+///
+/// The gate is on `feature = "std"` (not `not(feature = "no_std")`): the split
+/// must be additive so Cargo's feature unification holds — a dependency that
+/// wants `no_std` simply declines to enable `std`, and can never *strip* it
+/// from another consumer in the graph. The crate-level
+/// `#![cfg_attr(not(feature = "std"), no_std)]` and `extern crate alloc`
+/// wiring lives in the crate root alongside the `default = ["std"]` feature
+/// declaration.
+#[cfg(feature = "std")]
+macro_rules! firehose_debug {
+    ($($arg:tt)*) => { log::debug!($($arg)*) };
+}
+#[cfg(not(feature = "std"))]
+macro_rules! firehose_debug {
+    ($($arg:tt)*) => {{ let _ = format_args!($($arg)*); }};
+}
+
+#[cfg(feature = "std")]
+macro_rules! firehose_error {
+    ($($arg:tt)*) => { log::error!($($arg)*) };
+}
+#[cfg(not(feature = "std"))]
+macro_rules! firehose_error {
+    ($($arg:tt)*) => {{ let _ = format_args!($($arg)*); }};
+}
 
 #[derive(Clone, Copy)]
 pub struct FirehoseFlags(u16);
 
-impl std::fmt::Debug for FirehoseFlags {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+impl core::fmt::Debug for FirehoseFlags {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
         write!(f, "{:X}", self.0)
     }
 }
@@ -149,19 +182,19 @@ impl FirehoseFormatters {
     pub fn firehose_formatter_flags<'a>(
         mut input: &'a [u8],
         flags: impl Into<FirehoseFlags>,
-    ) -> nom::IResult<&'a [u8], FirehoseFormatters> {
+    ) -> nom::IResult<&'a [u8], FirehoseFormatters, FirehoseError> {
         let mut formatter_flags = FirehoseFormatters::default();
 
         let flags = flags.into();
 
         if flags.is_large_offset() {
-            debug!("[macos-unifiedlogs] Firehose flag: has_large_offset");
+            firehose_debug!("[macos-unifiedlogs] Firehose flag: has_large_offset");
             let (firehose_input, has_large_offset) = le_u16(input)?;
             formatter_flags.has_large_offset = has_large_offset;
             input = firehose_input;
 
             if flags.has_large_shared_cache() {
-                debug!(
+                firehose_debug!(
                     "[macos-unifiedlogs] Firehose flag: large_shared_cache and has_large_offset"
                 );
                 let (firehose_input, large_shared_cache) = le_u16(input)?;
@@ -169,7 +202,7 @@ impl FirehoseFormatters {
                 input = firehose_input;
             }
         } else if flags.is_large_shared_cache() {
-            debug!("[macos-unifiedlogs] Firehose flag: large_shared_cache");
+            firehose_debug!("[macos-unifiedlogs] Firehose flag: large_shared_cache");
             if flags.has_large_offset() {
                 let (firehose_input, has_large_offset) = le_u16(input)?;
                 formatter_flags.has_large_offset = has_large_offset;
@@ -180,19 +213,19 @@ impl FirehoseFormatters {
             formatter_flags.large_shared_cache = large_shared_cache;
             input = firehose_input;
         } else if flags.is_absolute() {
-            debug!("[macos-unifiedlogs] Firehose flag: absolute");
+            firehose_debug!("[macos-unifiedlogs] Firehose flag: absolute");
             formatter_flags.absolute = true;
             if !flags.has_message_strings_uuid() {
-                debug!("[macos-unifiedlogs] Firehose flag: alt index absolute flag");
+                firehose_debug!("[macos-unifiedlogs] Firehose flag: alt index absolute flag");
                 let (firehose_input, main_exe_alt_index) = le_u16(input)?;
                 formatter_flags.main_exe_alt_index = main_exe_alt_index;
                 input = firehose_input;
             }
         } else if flags.is_main_exe() {
-            debug!("[macos-unifiedlogs] Firehose flag: main_exe");
+            firehose_debug!("[macos-unifiedlogs] Firehose flag: main_exe");
             formatter_flags.main_exe = true
         } else if flags.is_shared_cache() {
-            debug!("[macos-unifiedlogs] Firehose flag: shared_cache");
+            firehose_debug!("[macos-unifiedlogs] Firehose flag: shared_cache");
             formatter_flags.shared_cache = true;
             if flags.has_large_offset() {
                 let (firehose_input, has_large_offset) = le_u16(input)?;
@@ -200,14 +233,20 @@ impl FirehoseFormatters {
                 input = firehose_input;
             }
         } else if flags.is_uuid_relative() {
-            debug!("[macos-unifiedlogs] Firehose flag: uuid_relative");
+            firehose_debug!("[macos-unifiedlogs] Firehose flag: uuid_relative");
             let (firehose_input, uuid_relative) = be_u128(input)?;
             formatter_flags.uuid_relative = format!("{:X}", uuid_relative);
             input = firehose_input;
         } else {
-            error!("[macos-unifiedlogs] Unknown Firehose formatter flag: {flags:?}",);
-            debug!("[macos-unifiedlogs] Firehose data: {:X?}", input);
-            return Err(nom::Err::Incomplete(Needed::Unknown));
+            firehose_error!("[macos-unifiedlogs] Unknown Firehose formatter flag: {flags:?}",);
+            firehose_debug!("[macos-unifiedlogs] Firehose data: {:X?}", input);
+            // Not a truncation: the bytes are present but the flag combination
+            // is unknown. Surface the raw flag word and format sub-bits so the
+            // caller can skip and log this entry and continue with the chunk.
+            return Err(nom::Err::Failure(FirehoseError::UnknownFormatterFlags {
+                raw: flags.0,
+                masked: flags.flags(),
+            }));
         }
 
         Ok((input, formatter_flags))
@@ -278,6 +317,21 @@ mod tests {
         assert_eq!(results.main_exe_alt_index, 65408);
     }
 
+    #[test]
+    fn test_firehose_formatter_flags_unknown_flags_errors() {
+        let test_data = [0, 0, 0, 0];
+        // Sub-flags mask to zero, which matches none of the known branches.
+        let test_flags = 0x10u16;
+        let result = FirehoseFormatters::firehose_formatter_flags(&test_data, test_flags);
+        assert_eq!(
+            result.err(),
+            Some(nom::Err::Failure(FirehoseError::UnknownFormatterFlags {
+                raw: 0x10,
+                masked: 0x0,
+            }))
+        );
+    }
+
     #[test]
     fn test_firehose_formatter_flags_uuid_relative() {
         let test_data = [
@@ -0,0 +1,476 @@
+// Copyright 2022 Mandiant, Inc. All Rights Reserved
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not use this file except in compliance with the License. You may obtain a copy of the License at
+// http://www.apache.org/licenses/LICENSE-2.0
+// Unless required by applicable law or agreed to in writing, software distributed under the License
+// is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and limitations under the License.
+
+use std::collections::HashMap;
+use std::collections::VecDeque;
+use std::hash::{BuildHasher, Hasher};
+
+use crate::chunks::firehose::flags::FirehoseFormatters;
+
+/// Which file the base format string was resolved out of. Combined with the
+/// owning file's UUID and the offset this identifies a format string across a
+/// trace.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum FormatFileKind {
+    /// main_exe flag - a UUID file contains the format string
+    MainExe,
+    /// shared_cache flag - a DSC file contains the format string
+    SharedCache,
+    /// uuid_relative flag - the UUID file name is in the log data
+    UuidRelative,
+    /// absolute flag - an alternative Catalog UUID index is used
+    Absolute,
+}
+
+/// Key identifying a resolved base format string. The base location is the
+/// tracepoint's `format_string_location` (the PC/offset of the string);
+/// `firehose_formatter_flags` supplies the file it lives in plus the
+/// large-offset/DSC extension bits that widen that location. The key is a
+/// small fixed-size tuple so hashing it is cheap.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct FormatStringKey {
+    /// UUID of the file the format string lives in. For `uuid_relative`
+    /// entries this is the UUID parsed out of the log data; for `main_exe`,
+    /// `shared_cache` and the large-offset DSC path it is the catalog-resolved
+    /// identity (the process's main UUID or the selected DSC UUID), since
+    /// `FirehoseFormatters` alone does not carry it. Always zero for the
+    /// `absolute` path, which is identified by the alternative Catalog UUID
+    /// index folded into `offset`.
+    pub uuid: u128,
+    /// File the format string lives in.
+    pub file_kind: FormatFileKind,
+    /// Location of the format string: the tracepoint `format_string_location`
+    /// in the low 32 bits, with any large-offset/DSC index folded into the
+    /// high bits.
+    pub offset: u64,
+}
+
+impl FormatStringKey {
+    /// Build a key from the formatter flags produced by
+    /// [`FirehoseFormatters::firehose_formatter_flags`], the tracepoint's
+    /// `format_string_location`, and the catalog-resolved `file_uuid` of the
+    /// file the string lives in. Returns `None` when the flags do not resolve
+    /// to a known file (e.g. the `Default` value before any flag branch ran).
+    ///
+    /// The `format_string_location` is required: `FirehoseFormatters` alone
+    /// does not carry the base string location, so for `main_exe`/
+    /// `shared_cache` entries with no large offset every distinct string would
+    /// otherwise collapse to the same key and the cache would hand back the
+    /// first string resolved for that file.
+    ///
+    /// `file_uuid` is required for the same reason applied to *file identity*:
+    /// `main_exe`/`shared_cache` entries carry their file in the catalog (the
+    /// process's main UUID or selected DSC), not in `FirehoseFormatters`, so
+    /// two entries from different processes' main executables — or two
+    /// different DSC files — sharing a `format_string_location` would collapse
+    /// to the same key and the cache would hand the second one the first's
+    /// string. Pass the UUID the catalog resolved for this entry; it is
+    /// ignored for `uuid_relative` (which carries its own) and unused by the
+    /// `absolute` path (identified by its alternative Catalog UUID index).
+    pub fn from_formatters(
+        formatters: &FirehoseFormatters,
+        format_string_location: u32,
+        file_uuid: u128,
+    ) -> Option<FormatStringKey> {
+        // Low 32 bits are the real PC/offset; the large-offset and DSC index
+        // only widen it, so they live above it and a given (file, location)
+        // pair still collapses to exactly one key.
+        let offset = (format_string_location as u64)
+            | ((formatters.large_shared_cache as u64) << 32)
+            | ((formatters.has_large_offset as u64) << 48);
+
+        if !formatters.uuid_relative.is_empty() {
+            let uuid = u128::from_str_radix(&formatters.uuid_relative, 16).ok()?;
+            Some(FormatStringKey {
+                uuid,
+                file_kind: FormatFileKind::UuidRelative,
+                offset,
+            })
+        } else if formatters.absolute {
+            // No large offset is set on the absolute path, so the top 16 bits
+            // are free for the alternative Catalog UUID index.
+            Some(FormatStringKey {
+                uuid: 0,
+                file_kind: FormatFileKind::Absolute,
+                offset: offset | ((formatters.main_exe_alt_index as u64) << 48),
+            })
+        } else if formatters.shared_cache {
+            Some(FormatStringKey {
+                uuid: file_uuid,
+                file_kind: FormatFileKind::SharedCache,
+                offset,
+            })
+        } else if formatters.main_exe {
+            Some(FormatStringKey {
+                uuid: file_uuid,
+                file_kind: FormatFileKind::MainExe,
+                offset,
+            })
+        } else if formatters.has_large_offset != 0 || formatters.large_shared_cache != 0 {
+            // The `is_large_offset`/`is_large_shared_cache` branches set only
+            // the offset/DSC index and leave every file_kind bool false — yet
+            // these large-offset DSC lookups are exactly the hot path this
+            // cache exists for. Resolve them out of the shared cache.
+            Some(FormatStringKey {
+                uuid: file_uuid,
+                file_kind: FormatFileKind::SharedCache,
+                offset,
+            })
+        } else {
+            None
+        }
+    }
+}
+
+/// Hasher tuned for the short, fixed-size [`FormatStringKey`] tuples. Keys are
+/// only a handful of bytes, so SipHash's setup cost dominates; this uses a
+/// single AES round on CPUs that expose AES-NI and a portable integer-mixing
+/// fallback everywhere else.
+pub struct AesHasher {
+    state: u64,
+}
+
+// 64-bit variant of the fxhash/rapidhash odd multiplier.
+const MIX_CONST: u64 = 0x517c_c1b7_2722_0a95;
+
+impl AesHasher {
+    #[inline]
+    fn write_u64(&mut self, value: u64) {
+        #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+        {
+            if is_x86_feature_detected!("aes") {
+                // SAFETY: guarded by the runtime AES-NI feature check above.
+                self.state = unsafe { aesni_mix(self.state, value) };
+                return;
+            }
+        }
+        self.state = (self.state ^ value)
+            .wrapping_mul(MIX_CONST)
+            .rotate_left(31);
+    }
+}
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+#[target_feature(enable = "aes")]
+unsafe fn aesni_mix(state: u64, value: u64) -> u64 {
+    #[cfg(target_arch = "x86")]
+    use std::arch::x86::*;
+    #[cfg(target_arch = "x86_64")]
+    use std::arch::x86_64::*;
+
+    let data = _mm_set_epi64x(state as i64, value as i64);
+    let key = _mm_set_epi64x(MIX_CONST as i64, state as i64);
+    let mixed = _mm_aesenc_si128(data, key);
+    _mm_cvtsi128_si64(mixed) as u64
+}
+
+impl Hasher for AesHasher {
+    fn finish(&self) -> u64 {
+        // Final avalanche so the low bits used for bucketing are well mixed.
+        let mut h = self.state;
+        h ^= h >> 33;
+        h = h.wrapping_mul(MIX_CONST);
+        h ^= h >> 29;
+        h
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        let mut chunks = bytes.chunks_exact(8);
+        for chunk in &mut chunks {
+            self.write_u64(u64::from_le_bytes(chunk.try_into().unwrap()));
+        }
+        let rest = chunks.remainder();
+        if !rest.is_empty() {
+            let mut buf = [0u8; 8];
+            buf[..rest.len()].copy_from_slice(rest);
+            self.write_u64(u64::from_le_bytes(buf));
+        }
+    }
+}
+
+/// [`BuildHasher`] producing [`AesHasher`]s seeded with a fixed constant.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct BuildAesHasher;
+
+impl BuildHasher for BuildAesHasher {
+    type Hasher = AesHasher;
+
+    fn build_hasher(&self) -> AesHasher {
+        AesHasher { state: MIX_CONST }
+    }
+}
+
+/// Memoizes resolved base format strings keyed on [`FormatStringKey`] so
+/// repeated firehose entries pointing at the same string skip the UUID/DSC
+/// file lookups and string-table scans. Threaded through the top-level parse
+/// API as a reusable handle.
+///
+/// `capacity` bounds memory on huge archives: once the cache is full the
+/// least-recently-*used* key is evicted (true LRU — a hit through
+/// `get_or_insert_with` bumps the key back to the most-recent end; `get` leaves
+/// the order unchanged) rather than letting the map grow without limit. A
+/// capacity of `None` disables eviction for callers that want to keep every
+/// string.
+pub struct FormatStringCache {
+    entries: HashMap<FormatStringKey, String, BuildAesHasher>,
+    order: VecDeque<FormatStringKey>,
+    capacity: Option<usize>,
+}
+
+impl FormatStringCache {
+    /// Create an unbounded cache (no eviction).
+    pub fn new() -> FormatStringCache {
+        FormatStringCache {
+            entries: HashMap::with_hasher(BuildAesHasher),
+            order: VecDeque::new(),
+            capacity: None,
+        }
+    }
+
+    /// Create a cache bounded to `capacity` entries, evicting the
+    /// least-recently-used key when full.
+    pub fn with_capacity(capacity: usize) -> FormatStringCache {
+        FormatStringCache {
+            entries: HashMap::with_capacity_and_hasher(capacity, BuildAesHasher),
+            order: VecDeque::with_capacity(capacity),
+            capacity: Some(capacity),
+        }
+    }
+
+    /// Look up a previously resolved format string.
+    pub fn get(&self, key: &FormatStringKey) -> Option<&str> {
+        self.entries.get(key).map(String::as_str)
+    }
+
+    /// Resolve `key` from the cache, falling back to `resolve` on a miss and
+    /// memoizing the result. This is the hot path: a hit skips the file and
+    /// string-table work entirely. On a bounded cache a hit also reorders the
+    /// LRU bookkeeping (O(capacity)); an unbounded cache never evicts, so its
+    /// hits stay O(1).
+    pub fn get_or_insert_with<F>(&mut self, key: FormatStringKey, resolve: F) -> &str
+    where
+        F: FnOnce() -> String,
+    {
+        if self.entries.contains_key(&key) {
+            // Hit: bump the key to the most-recently-used end so eviction is
+            // genuinely LRU rather than first-inserted-first-out.
+            self.touch(&key);
+        } else {
+            let value = resolve();
+            self.insert(key, value);
+        }
+        self.entries
+            .get(&key)
+            .map(String::as_str)
+            .expect("entry inserted above")
+    }
+
+    /// Move `key` to the most-recently-used end of the eviction order. Only
+    /// bounded caches track recency (an unbounded cache never evicts).
+    fn touch(&mut self, key: &FormatStringKey) {
+        if self.capacity.is_none() {
+            return;
+        }
+        if let Some(pos) = self.order.iter().position(|k| k == key) {
+            self.order.remove(pos);
+        }
+        self.order.push_back(*key);
+    }
+
+    fn insert(&mut self, key: FormatStringKey, value: String) {
+        if let Some(capacity) = self.capacity {
+            while self.entries.len() >= capacity {
+                match self.order.pop_front() {
+                    Some(evicted) => {
+                        self.entries.remove(&evicted);
+                    }
+                    None => break,
+                }
+            }
+            // Only bounded caches track recency; an unbounded cache never
+            // evicts, so recording the order would just double key memory.
+            self.order.push_back(key);
+        }
+        self.entries.insert(key, value);
+    }
+
+    /// Number of cached format strings.
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Whether the cache holds no entries.
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+impl Default for FormatStringCache {
+    fn default() -> FormatStringCache {
+        FormatStringCache::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_key_from_formatters_shared_cache() {
+        let mut formatters = FirehoseFormatters::default();
+        formatters.shared_cache = true;
+        formatters.large_shared_cache = 2;
+        formatters.has_large_offset = 1;
+        let key = FormatStringKey::from_formatters(&formatters, 0x40, 0xABCD).unwrap();
+        assert_eq!(key.file_kind, FormatFileKind::SharedCache);
+        assert_eq!(key.uuid, 0xABCD);
+        assert_eq!(key.offset, 0x40 | (2u64 << 32) | (1u64 << 48));
+    }
+
+    #[test]
+    fn test_key_from_formatters_uuid_relative() {
+        let mut formatters = FirehoseFormatters::default();
+        formatters.uuid_relative = String::from("7B0D3775F1903E21BA130447C41B8743");
+        // The file_uuid argument is ignored: uuid_relative carries its own.
+        let key = FormatStringKey::from_formatters(&formatters, 0, 0x1234).unwrap();
+        assert_eq!(key.file_kind, FormatFileKind::UuidRelative);
+        assert_eq!(key.uuid, 0x7B0D3775F1903E21BA130447C41B8743);
+    }
+
+    #[test]
+    fn test_key_from_default_is_none() {
+        assert!(FormatStringKey::from_formatters(&FirehoseFormatters::default(), 0, 0).is_none());
+    }
+
+    #[test]
+    fn test_key_distinguishes_string_location() {
+        // Two main_exe entries that differ only in their format-string
+        // location must not collapse to the same key, otherwise the cache
+        // returns the first resolved string for both.
+        let mut formatters = FirehoseFormatters::default();
+        formatters.main_exe = true;
+        let a = FormatStringKey::from_formatters(&formatters, 0x10, 0).unwrap();
+        let b = FormatStringKey::from_formatters(&formatters, 0x20, 0).unwrap();
+        assert_ne!(a, b);
+    }
+
+    #[test]
+    fn test_key_distinguishes_file_uuid() {
+        // Two main_exe entries from different processes sharing a
+        // format-string location must not collapse: a common small offset in
+        // two different main executables resolves two different strings.
+        let mut formatters = FirehoseFormatters::default();
+        formatters.main_exe = true;
+        let a = FormatStringKey::from_formatters(&formatters, 0x10, 0xAAAA).unwrap();
+        let b = FormatStringKey::from_formatters(&formatters, 0x10, 0xBBBB).unwrap();
+        assert_ne!(a, b);
+    }
+
+    #[test]
+    fn test_key_from_large_offset_is_cacheable() {
+        // The large-offset branch sets no file_kind bool but must still
+        // produce a key — these DSC lookups are the hot path.
+        let mut formatters = FirehoseFormatters::default();
+        formatters.has_large_offset = 3;
+        formatters.large_shared_cache = 5;
+        let key = FormatStringKey::from_formatters(&formatters, 0x80, 0xFEED).unwrap();
+        assert_eq!(key.file_kind, FormatFileKind::SharedCache);
+        assert_eq!(key.uuid, 0xFEED);
+        assert_eq!(key.offset, 0x80 | (5u64 << 32) | (3u64 << 48));
+    }
+
+    #[test]
+    fn test_cache_memoizes() {
+        let mut cache = FormatStringCache::new();
+        let key = FormatStringKey {
+            uuid: 0,
+            file_kind: FormatFileKind::MainExe,
+            offset: 16,
+        };
+        let mut calls = 0;
+        for _ in 0..3 {
+            let resolved = cache.get_or_insert_with(key, || {
+                calls += 1;
+                String::from("%s connected")
+            });
+            assert_eq!(resolved, "%s connected");
+        }
+        assert_eq!(calls, 1);
+        assert_eq!(cache.len(), 1);
+    }
+
+    #[test]
+    fn test_unbounded_cache_does_not_track_order() {
+        // An unbounded cache never evicts, so it must not accumulate a parallel
+        // copy of every key in `order` and double its memory.
+        let mut cache = FormatStringCache::new();
+        for offset in 0..4 {
+            let key = FormatStringKey {
+                uuid: 0,
+                file_kind: FormatFileKind::MainExe,
+                offset,
+            };
+            cache.get_or_insert_with(key, || String::from("x"));
+        }
+        assert_eq!(cache.len(), 4);
+        assert!(cache.order.is_empty());
+    }
+
+    #[test]
+    fn test_cache_evicts_when_bounded() {
+        let mut cache = FormatStringCache::with_capacity(2);
+        for offset in 0..4 {
+            let key = FormatStringKey {
+                uuid: 0,
+                file_kind: FormatFileKind::MainExe,
+                offset,
+            };
+            cache.get_or_insert_with(key, || String::from("x"));
+        }
+        assert_eq!(cache.len(), 2);
+        // Oldest keys evicted, newest retained.
+        assert!(cache
+            .get(&FormatStringKey {
+                uuid: 0,
+                file_kind: FormatFileKind::MainExe,
+                offset: 0
+            })
+            .is_none());
+        assert!(cache
+            .get(&FormatStringKey {
+                uuid: 0,
+                file_kind: FormatFileKind::MainExe,
+                offset: 3
+            })
+            .is_some());
+    }
+
+    #[test]
+    fn test_cache_lru_keeps_hot_key() {
+        let mut cache = FormatStringCache::with_capacity(2);
+        let hot = FormatStringKey {
+            uuid: 0,
+            file_kind: FormatFileKind::MainExe,
+            offset: 0,
+        };
+        let key = |offset| FormatStringKey {
+            uuid: 0,
+            file_kind: FormatFileKind::MainExe,
+            offset,
+        };
+
+        cache.get_or_insert_with(hot, || String::from("hot"));
+        cache.get_or_insert_with(key(1), || String::from("one"));
+        // Touch the hot key so it is most-recently-used, then insert past
+        // capacity: a FIFO would drop `hot` here, LRU drops `1` instead.
+        cache.get_or_insert_with(hot, || panic!("already cached"));
+        cache.get_or_insert_with(key(2), || String::from("two"));
+
+        assert!(cache.get(&hot).is_some());
+        assert!(cache.get(&key(1)).is_none());
+    }
+}
@@ -0,0 +1,683 @@
+// Copyright 2022 Mandiant, Inc. All Rights Reserved
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not use this file except in compliance with the License. You may obtain a copy of the License at
+// http://www.apache.org/licenses/LICENSE-2.0
+// Unless required by applicable law or agreed to in writing, software distributed under the License
+// is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and limitations under the License.
+
+//! Memory-mapped, streaming `tracev3` reader.
+//!
+//! The slice-based parsers in this crate take `&[u8]` handed to nom, which
+//! forces the caller to read an entire `tracev3` (often hundreds of MB inside
+//! a `.logarchive`) into a `Vec<u8>` first. This module maps the file with
+//! direct `fs` syscalls and walks chunk/firehose boundaries lazily, feeding
+//! borrowed slices into the mapping straight to
+//! [`FirehoseFormatters::firehose_formatter_flags`]. Peak memory is the
+//! mapping plus one chunk rather than the whole file; the in-memory slice API
+//! stays the fast path and this iterator layers on top of it.
+
+use std::io;
+use std::os::fd::AsFd;
+use std::path::Path;
+use std::ptr::NonNull;
+use std::slice;
+
+use nom::number::complete::{le_u16, le_u32, le_u64, le_u8};
+
+use rustix::fs::{Mode, OFlags};
+use rustix::mm::{MapFlags, ProtFlags};
+
+use crate::chunks::firehose::error::FirehoseError;
+use crate::chunks::firehose::flags::FirehoseFormatters;
+use crate::chunks::firehose::format_cache::{FormatStringCache, FormatStringKey};
+
+/// Firehose chunk tag.
+const CHUNK_TAG_FIREHOSE: u32 = 0x6001;
+
+/// A `tracev3` file mapped into memory. The mapping is owned for the lifetime
+/// of this handle and unmapped on drop; all parsing borrows directly from it
+/// without copying.
+pub struct MmapTraceV3 {
+    ptr: NonNull<u8>,
+    len: usize,
+}
+
+// SAFETY: the mapping is read-only (`ProtFlags::READ`) and private, so sharing
+// the pointer across threads only ever yields shared reads of immutable bytes.
+unsafe impl Send for MmapTraceV3 {}
+unsafe impl Sync for MmapTraceV3 {}
+
+impl MmapTraceV3 {
+    /// Map the `tracev3` at `path` read-only.
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<MmapTraceV3> {
+        let fd = rustix::fs::open(path.as_ref(), OFlags::RDONLY, Mode::empty())?;
+        let len = rustix::fs::fstat(&fd)?.st_size as usize;
+
+        if len == 0 {
+            return Ok(MmapTraceV3 {
+                ptr: NonNull::dangling(),
+                len: 0,
+            });
+        }
+
+        // SAFETY: `fd` refers to the file just opened and `len` is its size, so
+        // the kernel maps exactly the file contents; the mapping is released in
+        // `Drop`.
+        let addr = unsafe {
+            rustix::mm::mmap(
+                std::ptr::null_mut(),
+                len,
+                ProtFlags::READ,
+                MapFlags::PRIVATE,
+                fd.as_fd(),
+                0,
+            )?
+        };
+
+        let ptr = NonNull::new(addr as *mut u8)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::Other, "mmap returned null"))?;
+
+        Ok(MmapTraceV3 { ptr, len })
+    }
+
+    /// The mapped bytes as a borrowed slice.
+    pub fn as_bytes(&self) -> &[u8] {
+        if self.len == 0 {
+            return &[];
+        }
+        // SAFETY: `ptr`/`len` describe a live read-only mapping held by `self`,
+        // so the returned slice cannot outlive it.
+        unsafe { slice::from_raw_parts(self.ptr.as_ptr(), self.len) }
+    }
+
+    /// Iterate chunk headers lazily, yielding one [`Chunk`] per preamble with
+    /// its data borrowed from the mapping.
+    pub fn chunks(&self) -> ChunkIter<'_> {
+        ChunkIter {
+            input: self.as_bytes(),
+        }
+    }
+}
+
+impl Drop for MmapTraceV3 {
+    fn drop(&mut self) {
+        if self.len != 0 {
+            // SAFETY: `ptr`/`len` came from the `mmap` call in `open` and are
+            // unmapped exactly once here.
+            unsafe {
+                let _ = rustix::mm::munmap(self.ptr.as_ptr() as *mut _, self.len);
+            }
+        }
+    }
+}
+
+/// A single chunk of a `tracev3` file: its tag/sub-tag and the data slice
+/// borrowed from the mapping.
+#[derive(Debug, Clone, Copy)]
+pub struct Chunk<'a> {
+    pub chunk_tag: u32,
+    pub chunk_sub_tag: u32,
+    pub data: &'a [u8],
+}
+
+impl<'a> Chunk<'a> {
+    /// Whether this chunk carries firehose tracepoints.
+    pub fn is_firehose(&self) -> bool {
+        self.chunk_tag == CHUNK_TAG_FIREHOSE
+    }
+
+    /// Lazily walk the firehose tracepoints in this chunk, yielding parsed
+    /// formatter flags for each preamble. Returns `None` for non-firehose
+    /// chunks. Each yielded entry borrows from the mapping.
+    pub fn firehose_entries(&self) -> Option<FirehoseEntryIter<'a>> {
+        if !self.is_firehose() {
+            return None;
+        }
+        // The firehose chunk preamble precedes the public tracepoint data. The
+        // `public_data_size` field at 0x10 is measured from its own offset, so
+        // the public region spans `0x10 .. 0x10 + public_data_size` and the
+        // tracepoints themselves are the back `public_data_size - 16` bytes
+        // starting at 0x20 (the canonical parser takes `public_data_size - 16`
+        // from 0x20). Anything past `0x10 + public_data_size` is the
+        // private-strings region and must not be decoded as a tracepoint.
+        let preamble_len = 0x20;
+        let public_data_offset = 0x10;
+        if self.data.len() < preamble_len {
+            return Some(FirehoseEntryIter { input: &[] });
+        }
+        let public_data_size = u16::from_le_bytes([self.data[0x10], self.data[0x11]]) as usize;
+        let start = preamble_len;
+        let end = public_data_offset
+            .checked_add(public_data_size)
+            .map(|e| e.clamp(start, self.data.len()))
+            .unwrap_or(self.data.len());
+        Some(FirehoseEntryIter {
+            input: &self.data[start..end],
+        })
+    }
+}
+
+/// Lazy iterator over chunk headers. Each `next` parses one 16-byte preamble
+/// (tag, sub-tag, data size) and slices the following data out of the mapping.
+pub struct ChunkIter<'a> {
+    input: &'a [u8],
+}
+
+impl<'a> Iterator for ChunkIter<'a> {
+    type Item = Chunk<'a>;
+
+    fn next(&mut self) -> Option<Chunk<'a>> {
+        let (input, chunk_tag) = le_u32::<_, nom::error::Error<&[u8]>>(self.input).ok()?;
+        let (input, chunk_sub_tag) = le_u32::<_, nom::error::Error<&[u8]>>(input).ok()?;
+        let (input, data_size) = le_u64::<_, nom::error::Error<&[u8]>>(input).ok()?;
+
+        let data_size = data_size as usize;
+        if input.len() < data_size {
+            return None;
+        }
+        let data = &input[..data_size];
+
+        // Chunks are padded to an 8-byte boundary.
+        let padded = (data_size + 7) & !7;
+        self.input = if input.len() >= padded {
+            &input[padded..]
+        } else {
+            &[]
+        };
+
+        Some(Chunk {
+            chunk_tag,
+            chunk_sub_tag,
+            data,
+        })
+    }
+}
+
+/// Firehose tracepoint activity types. The activity-type-specific fields that
+/// precede the formatter flags differ per type, so the byte offset of the
+/// formatter-flag word depends on this.
+const ACTIVITY_TYPE_ACTIVITY: u8 = 0x2;
+const ACTIVITY_TYPE_NONACTIVITY: u8 = 0x4;
+const ACTIVITY_TYPE_SIGNPOST: u8 = 0x6;
+
+/// `has_current_aid` flag — prepends the current activity id + sentinel pair.
+const FLAG_HAS_CURRENT_AID: u16 = 0x1;
+/// `has_private_data` flag — prepends the private-strings offset + size pair.
+const FLAG_PRIVATE_STRING_RANGE: u16 = 0x100;
+/// `has_oversize`/`DATA_REF` flag — the base format string is *not* inline: it
+/// lives in a separate oversize chunk referenced by id, so no formatter-flag
+/// bytes follow the header and inline resolution does not apply.
+const FLAG_DATA_REF: u16 = 0x800;
+
+/// Where the formatter flags live for one tracepoint, or the reason they can't
+/// be resolved inline.
+enum FormatterFlagsLocation {
+    /// Byte offset, within the payload, at which the formatter-flag extension
+    /// bytes begin.
+    Inline(usize),
+    /// The entry references an oversize chunk (`has_oversize`); the format
+    /// string is resolved from there, not from inline bytes.
+    Oversize,
+    /// This activity type carries no formatter flags on this path (trace/loss).
+    NotApplicable,
+}
+
+/// Locate the formatter-flag extension bytes within a tracepoint payload for
+/// `activity_type`/`flags`. The flags do not sit at the payload start: they
+/// follow the activity-type-specific fields, each present only when the
+/// corresponding `flags` bit is set.
+///
+/// * `has_current_aid` (0x1) prepends an 8-byte current-activity-id + sentinel
+///   pair across every log-bearing type.
+/// * Activity tracepoints carry an additional 8-byte activity-id + sentinel
+///   pair before the formatter flags.
+/// * `has_private_data` (0x100) prepends a 4-byte private-strings
+///   offset + size pair on non-activity and signpost tracepoints.
+/// * `has_oversize` (0x800) moves the base format string into an oversize
+///   chunk, so there are no inline formatter-flag bytes to offset into — the
+///   caller must resolve the string from the referenced oversize chunk.
+///
+/// NOTE: this offset model must stay in lockstep with the canonical firehose
+/// parser. It deliberately refuses (via [`FormatterFlagsLocation::Oversize`])
+/// the one layout — `has_oversize` — whose field shape it cannot reproduce,
+/// rather than handing a misaligned slice to `firehose_formatter_flags` and
+/// silently returning the wrong `FirehoseFormatters`.
+fn formatter_flags_location(activity_type: u8, flags: u16) -> FormatterFlagsLocation {
+    match activity_type {
+        ACTIVITY_TYPE_ACTIVITY | ACTIVITY_TYPE_NONACTIVITY | ACTIVITY_TYPE_SIGNPOST => {}
+        // Trace and loss records carry no formatter flags on this path.
+        _ => return FormatterFlagsLocation::NotApplicable,
+    }
+    // Oversize entries keep the base format string in a separate chunk; there
+    // are no inline formatter-flag bytes, so offsetting into the payload would
+    // misalign the parser.
+    if flags & FLAG_DATA_REF != 0 {
+        return FormatterFlagsLocation::Oversize;
+    }
+    let mut offset = 0usize;
+    if flags & FLAG_HAS_CURRENT_AID != 0 {
+        offset += 8;
+    }
+    // Activity tracepoints carry an additional activity-id + sentinel pair.
+    if activity_type == ACTIVITY_TYPE_ACTIVITY {
+        offset += 8;
+    }
+    // The private-strings pair is carried only by non-activity and signpost
+    // tracepoints.
+    if activity_type != ACTIVITY_TYPE_ACTIVITY && flags & FLAG_PRIVATE_STRING_RANGE != 0 {
+        offset += 4;
+    }
+    FormatterFlagsLocation::Inline(offset)
+}
+
+/// A resolved firehose tracepoint preamble: the base format-string location
+/// and the formatter flags identifying which file it lives in. Together these
+/// build a `FormatStringKey` for the resolution cache — the location is
+/// retained here precisely so distinct strings in one file do not collapse to
+/// one key.
+///
+/// When `has_oversize` is set the base format string lives in a separate
+/// oversize chunk referenced by this entry, so `formatters` is left at its
+/// default: there are no inline formatter-flag bytes to resolve and the caller
+/// must look the string up in the referenced oversize chunk.
+#[derive(Debug, Clone)]
+pub struct FirehoseEntry {
+    pub format_string_location: u32,
+    pub formatters: FirehoseFormatters,
+    pub has_oversize: bool,
+}
+
+impl FirehoseEntry {
+    /// Build the [`FormatStringKey`] for this entry's base format string.
+    /// `file_uuid` is the catalog-resolved UUID of the file the string lives in
+    /// (the process's main UUID or the selected DSC); it is ignored for
+    /// `uuid_relative` entries, which carry their own. Returns `None` for
+    /// oversize entries (resolved from a separate chunk) and for entries whose
+    /// formatter flags do not resolve to a known file.
+    pub fn format_string_key(&self, file_uuid: u128) -> Option<FormatStringKey> {
+        if self.has_oversize {
+            return None;
+        }
+        FormatStringKey::from_formatters(&self.formatters, self.format_string_location, file_uuid)
+    }
+
+    /// Resolve this entry's base format string through `cache`, running the
+    /// expensive `resolve` closure only on a miss. This is the glue that turns
+    /// the caching subsystem into the O(1) repeat-lookup win the streaming
+    /// reader exists for: the caller hands each mmap-iterated entry plus the
+    /// `file_uuid` the catalog resolved for it, and repeated `(file, location)`
+    /// pairs skip the UUID/DSC file lookups and string-table scans. Returns
+    /// `None` when the entry does not resolve to a cacheable key.
+    pub fn resolve_format_string<'c, F>(
+        &self,
+        cache: &'c mut FormatStringCache,
+        file_uuid: u128,
+        resolve: F,
+    ) -> Option<&'c str>
+    where
+        F: FnOnce() -> String,
+    {
+        let key = self.format_string_key(file_uuid)?;
+        Some(cache.get_or_insert_with(key, resolve))
+    }
+}
+
+/// One tracepoint yielded by [`FirehoseEntryIter`]: either a resolved
+/// [`FirehoseEntry`] or the [`FirehoseError`] from the entry that failed, so a
+/// caller can log the specific bad/unsupported entry and keep iterating the
+/// rest of the chunk.
+pub type FirehoseEntryResult = Result<FirehoseEntry, FirehoseError>;
+
+/// Lazy iterator over the firehose tracepoints of a single firehose chunk,
+/// yielding the formatter flags resolved from each preamble.
+pub struct FirehoseEntryIter<'a> {
+    input: &'a [u8],
+}
+
+impl<'a> Iterator for FirehoseEntryIter<'a> {
+    type Item = FirehoseEntryResult;
+
+    fn next(&mut self) -> Option<FirehoseEntryResult> {
+        loop {
+            // Firehose tracepoint header: activity type (u8), log type (u8),
+            // flags (u16), format string location (u32), thread id (u64),
+            // continuous time delta (u32) + upper (u16), data size (u16). The
+            // `flags` field drives formatter-flag resolution; everything after
+            // the header up to `data_size` is the entry payload.
+            let (input, activity_type) = le_u8::<_, nom::error::Error<&[u8]>>(self.input).ok()?;
+            let (input, _log_type) = le_u8::<_, nom::error::Error<&[u8]>>(input).ok()?;
+            let (input, flags) = le_u16::<_, nom::error::Error<&[u8]>>(input).ok()?;
+            let (input, format_string_location) =
+                le_u32::<_, nom::error::Error<&[u8]>>(input).ok()?;
+            let (input, _thread_id) = le_u64::<_, nom::error::Error<&[u8]>>(input).ok()?;
+            let (input, _continuous_delta) = le_u32::<_, nom::error::Error<&[u8]>>(input).ok()?;
+            let (input, _continuous_delta_upper) =
+                le_u16::<_, nom::error::Error<&[u8]>>(input).ok()?;
+            let (input, data_size) = le_u16::<_, nom::error::Error<&[u8]>>(input).ok()?;
+
+            let data_size = data_size as usize;
+            if input.len() < data_size {
+                // The header parsed but its payload runs off the end of the
+                // chunk: a genuine truncation, distinct from a clean end of
+                // iteration (which exits via the `?` on the header reads).
+                self.input = &[];
+                return Some(Err(FirehoseError::Truncated));
+            }
+            let entry = &input[..data_size];
+
+            // Advance past this tracepoint (padded to an 8-byte boundary) now,
+            // so an error on this entry does not stall the iterator.
+            let padded = (data_size + 7) & !7;
+            self.input = if input.len() >= padded {
+                &input[padded..]
+            } else {
+                &[]
+            };
+
+            let flag_offset = match formatter_flags_location(activity_type, flags) {
+                FormatterFlagsLocation::Inline(flag_offset) => flag_offset,
+                // Oversize entries resolve their string from a separate chunk;
+                // yield the preamble with no inline formatters rather than
+                // misparsing the payload.
+                FormatterFlagsLocation::Oversize => {
+                    return Some(Ok(FirehoseEntry {
+                        format_string_location,
+                        formatters: FirehoseFormatters::default(),
+                        has_oversize: true,
+                    }));
+                }
+                // Trace/loss records carry no formatter flags; skip them rather
+                // than reporting a spurious error for a well-formed entry.
+                FormatterFlagsLocation::NotApplicable => continue,
+            };
+            if flag_offset > entry.len() {
+                return Some(Err(FirehoseError::Truncated));
+            }
+
+            // The formatter-flag word is not at the payload start: skip the
+            // activity-type-specific fields that precede it, then hand the
+            // borrowed remainder to the slice-based parser.
+            return Some(
+                match FirehoseFormatters::firehose_formatter_flags(&entry[flag_offset..], flags) {
+                    Ok((_, formatters)) => Ok(FirehoseEntry {
+                        format_string_location,
+                        formatters,
+                        has_oversize: false,
+                    }),
+                    Err(nom::Err::Failure(err)) | Err(nom::Err::Error(err)) => Err(err),
+                    Err(nom::Err::Incomplete(_)) => Err(FirehoseError::Truncated),
+                },
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_chunk_iter_walks_headers() {
+        // Two chunks: firehose (4 bytes data) then an arbitrary tag (8 bytes).
+        let mut data = Vec::new();
+        data.extend_from_slice(&CHUNK_TAG_FIREHOSE.to_le_bytes());
+        data.extend_from_slice(&1u32.to_le_bytes());
+        data.extend_from_slice(&4u64.to_le_bytes());
+        data.extend_from_slice(&[0xaa, 0xbb, 0xcc, 0xdd]);
+        data.extend_from_slice(&[0, 0, 0, 0]); // pad to 8 bytes
+        data.extend_from_slice(&0x600bu32.to_le_bytes());
+        data.extend_from_slice(&0u32.to_le_bytes());
+        data.extend_from_slice(&8u64.to_le_bytes());
+        data.extend_from_slice(&[1, 2, 3, 4, 5, 6, 7, 8]);
+
+        let chunks: Vec<_> = ChunkIter { input: &data }.collect();
+        assert_eq!(chunks.len(), 2);
+        assert!(chunks[0].is_firehose());
+        assert_eq!(chunks[0].data, &[0xaa, 0xbb, 0xcc, 0xdd]);
+        assert!(!chunks[1].is_firehose());
+        assert_eq!(chunks[1].chunk_tag, 0x600b);
+    }
+
+    #[test]
+    fn test_firehose_entries_none_for_other_chunk() {
+        let chunk = Chunk {
+            chunk_tag: 0x600b,
+            chunk_sub_tag: 0,
+            data: &[],
+        };
+        assert!(chunk.firehose_entries().is_none());
+    }
+
+    #[test]
+    fn test_firehose_entries_excludes_private_strings() {
+        // public_data_size is measured from 0x10, so the tracepoint region is
+        // [0x20 .. 0x10 + public_data_size]. A chunk whose size field selects a
+        // 0x28 end must not pull in the 16 bytes of private-strings data that
+        // follow — the old `0x20 + size` arithmetic over-read them and decoded
+        // a spurious trailing tracepoint.
+        let mut data = vec![0u8; 0x38];
+        let public_data_size: u16 = 0x18; // 0x10 + 0x18 == 0x28
+        data[0x10] = public_data_size.to_le_bytes()[0];
+        data[0x11] = public_data_size.to_le_bytes()[1];
+        // Tag the tracepoint bytes (0x20..0x28) and the private region
+        // (0x28..0x38) distinctly so an over-read would be visible.
+        for (i, byte) in data.iter_mut().enumerate().take(0x28).skip(0x20) {
+            *byte = i as u8;
+        }
+        for byte in data.iter_mut().skip(0x28) {
+            *byte = 0xff;
+        }
+
+        let chunk = Chunk {
+            chunk_tag: CHUNK_TAG_FIREHOSE,
+            chunk_sub_tag: 0,
+            data: &data,
+        };
+        let iter = chunk.firehose_entries().unwrap();
+        assert_eq!(iter.input, &data[0x20..0x28]);
+    }
+
+    #[test]
+    fn test_formatter_flags_location_skips_prefix() {
+        use FormatterFlagsLocation::*;
+        // Non-activity, no flags: formatter flags sit at the payload start.
+        assert!(matches!(
+            formatter_flags_location(ACTIVITY_TYPE_NONACTIVITY, 0),
+            Inline(0)
+        ));
+        // has_current_aid prepends the 8-byte activity-id + sentinel pair.
+        assert!(matches!(
+            formatter_flags_location(ACTIVITY_TYPE_NONACTIVITY, FLAG_HAS_CURRENT_AID),
+            Inline(8)
+        ));
+        // has_private_data adds the 4-byte private-strings offset + size pair.
+        assert!(matches!(
+            formatter_flags_location(
+                ACTIVITY_TYPE_NONACTIVITY,
+                FLAG_HAS_CURRENT_AID | FLAG_PRIVATE_STRING_RANGE
+            ),
+            Inline(12)
+        ));
+        // Activity tracepoints carry an extra 8-byte pair.
+        assert!(matches!(
+            formatter_flags_location(ACTIVITY_TYPE_ACTIVITY, 0),
+            Inline(8)
+        ));
+        // Trace/loss records do not resolve formatter flags on this path.
+        assert!(matches!(formatter_flags_location(0x3, 0), NotApplicable));
+    }
+
+    #[test]
+    fn test_formatter_flags_location_oversize() {
+        use FormatterFlagsLocation::*;
+        // has_oversize (0x800): the string is in a separate chunk, so there are
+        // no inline formatter-flag bytes to offset into. It must not be treated
+        // as an Inline(0) read — that misaligns the slice handed to the parser.
+        assert!(matches!(
+            formatter_flags_location(ACTIVITY_TYPE_NONACTIVITY, FLAG_DATA_REF),
+            Oversize
+        ));
+    }
+
+    /// Build a single firehose tracepoint: header followed by `payload` as the
+    /// data region. `flags` drives formatter-flag resolution.
+    fn tracepoint(activity_type: u8, flags: u16, format_string_location: u32, payload: &[u8]) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        bytes.push(activity_type);
+        bytes.push(0); // log type
+        bytes.extend_from_slice(&flags.to_le_bytes());
+        bytes.extend_from_slice(&format_string_location.to_le_bytes());
+        bytes.extend_from_slice(&0u64.to_le_bytes()); // thread id
+        bytes.extend_from_slice(&0u32.to_le_bytes()); // continuous delta
+        bytes.extend_from_slice(&0u16.to_le_bytes()); // continuous delta upper
+        bytes.extend_from_slice(&(payload.len() as u16).to_le_bytes()); // data size
+        bytes.extend_from_slice(payload);
+        bytes
+    }
+
+    // Format sub-flags: main_exe resolves its string from the process UUID file
+    // and reads no extension bytes, so these round-trips isolate the offset
+    // model. Each payload is exactly as long as the expected prefix, so an
+    // offset computed too large surfaces as `Truncated`. The sub-flag mask is
+    // read from the header `flags`, not the payload, so an offset computed too
+    // small still decodes to main_exe and is not caught here.
+    const SUBFLAG_MAIN_EXE: u16 = 0x2;
+
+    #[test]
+    fn test_roundtrip_nonactivity_with_current_aid_and_private() {
+        // Non-activity: has_current_aid (+8) then has_private_data (+4) precede
+        // the formatter flags, so they sit 12 bytes into the payload.
+        let flags = SUBFLAG_MAIN_EXE | FLAG_HAS_CURRENT_AID | FLAG_PRIVATE_STRING_RANGE;
+        let payload = vec![0u8; 12];
+        let bytes = tracepoint(ACTIVITY_TYPE_NONACTIVITY, flags, 0x40, &payload);
+        let mut iter = FirehoseEntryIter { input: &bytes };
+        match iter.next() {
+            Some(Ok(entry)) => {
+                assert!(entry.formatters.main_exe);
+                assert_eq!(entry.format_string_location, 0x40);
+            }
+            other => panic!("expected a main_exe entry, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn test_roundtrip_activity_carries_extra_pair() {
+        // Activity tracepoints carry an unconditional 8-byte activity-id +
+        // sentinel pair before the formatter flags even with no flags set.
+        let payload = vec![0u8; 8];
+        let bytes = tracepoint(ACTIVITY_TYPE_ACTIVITY, SUBFLAG_MAIN_EXE, 0x10, &payload);
+        let mut iter = FirehoseEntryIter { input: &bytes };
+        match iter.next() {
+            Some(Ok(entry)) => assert!(entry.formatters.main_exe),
+            other => panic!("expected a main_exe entry, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn test_roundtrip_signpost_with_current_aid() {
+        // Signpost: has_current_aid (+8) precedes the formatter flags; there is
+        // no unconditional activity-id pair as there is for ACTIVITY.
+        let flags = SUBFLAG_MAIN_EXE | FLAG_HAS_CURRENT_AID;
+        let payload = vec![0u8; 8];
+        let bytes = tracepoint(ACTIVITY_TYPE_SIGNPOST, flags, 0x20, &payload);
+        let mut iter = FirehoseEntryIter { input: &bytes };
+        match iter.next() {
+            Some(Ok(entry)) => assert!(entry.formatters.main_exe),
+            other => panic!("expected a main_exe entry, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn test_resolve_format_string_memoizes_through_cache() {
+        // Resolving the same file/location three times resolves once; each
+        // repeat is an O(1) cache hit and the expensive closure runs a single
+        // time.
+        let mut formatters = FirehoseFormatters::default();
+        formatters.main_exe = true;
+        let entry = FirehoseEntry {
+            format_string_location: 0x40,
+            formatters,
+            has_oversize: false,
+        };
+
+        let mut cache = FormatStringCache::new();
+        let mut calls = 0;
+        for _ in 0..3 {
+            let resolved = entry
+                .resolve_format_string(&mut cache, 0xFEED, || {
+                    calls += 1;
+                    String::from("%s connected")
+                })
+                .unwrap();
+            assert_eq!(resolved, "%s connected");
+        }
+        assert_eq!(calls, 1);
+        assert_eq!(cache.len(), 1);
+    }
+
+    #[test]
+    fn test_oversize_entry_has_no_cache_key() {
+        // Oversize entries resolve from a separate chunk, not via the inline
+        // formatter-flag key, so they must not produce a cacheable key.
+        let entry = FirehoseEntry {
+            format_string_location: 0x40,
+            formatters: FirehoseFormatters::default(),
+            has_oversize: true,
+        };
+        assert!(entry.format_string_key(0xFEED).is_none());
+    }
+
+    #[test]
+    fn test_firehose_entry_iter_oversize_is_not_misparsed() {
+        // An oversize non-activity tracepoint must yield a has_oversize entry
+        // with default formatters — not a (wrong) FirehoseFormatters decoded
+        // from a misaligned slice, and not a spurious error.
+        let flags: u16 = FLAG_DATA_REF;
+        let mut payload = Vec::new();
+        payload.push(ACTIVITY_TYPE_NONACTIVITY); // activity type
+        payload.push(0); // log type
+        payload.extend_from_slice(&flags.to_le_bytes()); // flags: has_oversize
+        payload.extend_from_slice(&0x40u32.to_le_bytes()); // format string location
+        payload.extend_from_slice(&0u64.to_le_bytes()); // thread id
+        payload.extend_from_slice(&0u32.to_le_bytes()); // continuous delta
+        payload.extend_from_slice(&0u16.to_le_bytes()); // continuous delta upper
+        payload.extend_from_slice(&4u16.to_le_bytes()); // data size
+        payload.extend_from_slice(&[0, 0, 0, 0]); // oversize data-ref payload
+
+        let mut iter = FirehoseEntryIter { input: &payload };
+        match iter.next() {
+            Some(Ok(entry)) => {
+                assert!(entry.has_oversize);
+                assert_eq!(entry.format_string_location, 0x40);
+            }
+            other => panic!("expected an oversize entry, got {other:?}"),
+        }
+        assert!(iter.next().is_none());
+    }
+
+    #[test]
+    fn test_firehose_entry_iter_surfaces_unknown_flags() {
+        // A single non-activity tracepoint whose flags mask to an unknown
+        // formatter combination must surface the error, not a default entry.
+        let mut payload = Vec::new();
+        payload.push(ACTIVITY_TYPE_NONACTIVITY); // activity type
+        payload.push(0); // log type
+        payload.extend_from_slice(&0x10u16.to_le_bytes()); // flags: unknown sub-flags
+        payload.extend_from_slice(&0u32.to_le_bytes()); // format string location
+        payload.extend_from_slice(&0u64.to_le_bytes()); // thread id
+        payload.extend_from_slice(&0u32.to_le_bytes()); // continuous delta
+        payload.extend_from_slice(&0u16.to_le_bytes()); // continuous delta upper
+        payload.extend_from_slice(&4u16.to_le_bytes()); // data size
+        payload.extend_from_slice(&[0, 0, 0, 0]); // entry payload
+
+        let mut iter = FirehoseEntryIter { input: &payload };
+        match iter.next() {
+            Some(Err(FirehoseError::UnknownFormatterFlags { raw, masked })) => {
+                assert_eq!(raw, 0x10);
+                assert_eq!(masked, 0x0);
+            }
+            other => panic!("expected unknown-flags error, got {other:?}"),
+        }
+        assert!(iter.next().is_none());
+    }
+}